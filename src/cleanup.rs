@@ -0,0 +1,104 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use log::warn;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Remove stale per-hash temp storage directories under `<storage_dir>/storage`
+///
+/// A directory is stale once nothing inside it has been modified for at least
+/// `older_than`. Hashes listed in `active` are always kept. Returns the number
+/// of directories removed and the number of bytes they held.
+pub fn gc(
+    storage_dir: &str,
+    older_than: Duration,
+    active: &HashSet<String>,
+) -> io::Result<(usize, u64)> {
+    let storage = Path::new(storage_dir).join("storage");
+    if !storage.exists() {
+        return Ok((0, 0));
+    }
+
+    let mut dirs = 0;
+    let mut bytes = 0;
+
+    for entry in fs::read_dir(&storage)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        let hash = match path.file_name().and_then(|name| name.to_str()) {
+            Some(hash) => hash.to_owned(),
+            None => continue,
+        };
+        if active.contains(&hash) {
+            continue;
+        }
+
+        let (modified, size) = match scan_dir(&path) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("Failed to inspect temp storage {}: {}", hash, err);
+                continue;
+            }
+        };
+
+        let age = modified.elapsed().unwrap_or_default();
+        if age < older_than {
+            continue;
+        }
+
+        match fs::remove_dir_all(&path) {
+            Ok(()) => {
+                dirs += 1;
+                bytes += size;
+            }
+            Err(err) => warn!("Failed to remove temp storage {}: {}", hash, err),
+        }
+    }
+
+    Ok((dirs, bytes))
+}
+
+// Find the most recent modification time of a directory and everything in it,
+// along with the total size of the files it holds
+fn scan_dir(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let mut modified = fs::metadata(path)?.modified()?;
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        let (entry_modified, entry_size) = if metadata.is_dir() {
+            scan_dir(&entry.path())?
+        } else {
+            (metadata.modified()?, metadata.len())
+        };
+
+        if entry_modified > modified {
+            modified = entry_modified;
+        }
+        size += entry_size;
+    }
+
+    Ok((modified, size))
+}
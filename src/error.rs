@@ -0,0 +1,40 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use failure::Fail;
+use std::fmt;
+
+/// Errors which can be encountered while running the file transfer service
+#[derive(Debug)]
+pub enum ServiceError {
+    /// The configured downlink destination is not in the `allowed_downlinks` list
+    DownlinkNotAllowed {
+        /// The refused destination address
+        addr: String,
+    },
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::DownlinkNotAllowed { addr } => {
+                write!(f, "Downlink to {} is not allowed", addr)
+            }
+        }
+    }
+}
+
+impl Fail for ServiceError {}
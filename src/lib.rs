@@ -16,20 +16,194 @@
 
 #![allow(clippy::block_in_if_condition_stmt)]
 
+mod cleanup;
+mod error;
+mod paths;
+mod state;
+mod templates;
+mod transfer;
+
+pub use crate::cleanup::gc;
+pub use crate::error::ServiceError;
+pub use crate::state::StateUsage;
+
+use crate::state::StateBudget;
+use crate::transfer::TransferInfo;
 use log::debug;
-use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_protocol::{FileProtocol, FileProtocolConfig, Message, ProtocolError, State};
 use kubos_system::Config as ServiceConfig;
 use log::{error, info, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::net::UdpSocket;
+use std::path::Path;
 use notify::{Watcher, RecursiveMode, PollWatcher, EventKind};
 
+// Map of open channel IDs to their handler's sender and the time the channel was opened
+type Threads = HashMap<u32, (Sender<Vec<u8>>, Instant)>;
+
+// Map of source paths to the prepared (file name, hash, chunk count, mode) of each file
+type StoredFiles = HashMap<String, (String, String, u32, u32)>;
+
+// Map of open channel IDs to the transfer each one is carrying
+type Transfers = HashMap<u32, TransferInfo>;
+
+// Map of recently completed (or reaped) channel IDs to their completion time, along
+// with the transfer they finished
+type Completed = HashMap<u32, (Instant, Option<TransferInfo>)>;
+
+// Bookkeeping for bounding the number of channels being processed at once. Queued
+// channels keep the time they started waiting.
+struct TransferSlots {
+    active: usize,
+    queued: VecDeque<(u32, Receiver<Vec<u8>>, Instant)>,
+}
+
+// A handler thread's hold on one of the transfer slots, which is given up when the
+// thread runs out of queued channels, or if it panics
+struct SlotGuard {
+    slots: Arc<Mutex<TransferSlots>>,
+    held: bool,
+}
+
+impl SlotGuard {
+    // Take the next queued channel, or give up the slot if there isn't one. Both
+    // happen under the slots lock, so a channel can't be queued in between and
+    // left waiting with no handler to pick it up.
+    fn next_queued(&mut self, threads: &Mutex<Threads>) -> Option<(u32, Receiver<Vec<u8>>)> {
+        let mut slots = self
+            .slots
+            .lock()
+            .map_err(|err| {
+                error!("Failed to get transfer slots mutex: {:?}", err);
+                err
+            })
+            .unwrap();
+
+        match slots.queued.pop_front() {
+            Some((channel_id, receiver, _)) => {
+                // The channel's lifetime only starts once it has a handler
+                if let Some((_, opened)) = threads
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get threads mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .get_mut(&channel_id)
+                {
+                    *opened = Instant::now();
+                }
+                Some((channel_id, receiver))
+            }
+            None => {
+                slots.active -= 1;
+                self.held = false;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        if self.held {
+            // We may be unwinding from a panic while another handler held the lock, but
+            // the count itself is still good
+            self.slots
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .active -= 1;
+        }
+    }
+}
+
+// The setuid, setgid and sticky bits of a file mode
+const SPECIAL_MODE_BITS: u32 = 0o7000;
+
+// Tell a client that its channel is being refused, so that it fails quickly rather
+// than waiting out its timeout
+fn refuse_channel(socket: &UdpSocket, downlink: &str, channel_id: u32, reason: &str) {
+    warn!("Refusing channel {}: {}", channel_id, reason);
+    if let Some(failure) = transfer::failure_message(channel_id, reason) {
+        if let Err(e) = socket.send_to(&failure, downlink) {
+            warn!("Failed to send failure for channel {}: {:?}", channel_id, e);
+        }
+    }
+}
+
+// Evict the least recently used prepared files from the stored files map until it's
+// back within its budget, keeping any which an open channel is using, and re-measure
+// what's left
+fn reclaim_state(
+    stored_files: &Mutex<StoredFiles>,
+    budget: &Mutex<StateBudget>,
+    transfers: &Mutex<Transfers>,
+    usage: &StateUsage,
+) {
+    let (hashes, sources): (HashSet<String>, HashSet<String>) = {
+        let transfers = transfers
+            .lock()
+            .map_err(|err| {
+                error!("Failed to get transfers mutex: {:?}", err);
+                err
+            })
+            .unwrap();
+        (
+            transfers.values().filter_map(|info| info.hash.clone()).collect(),
+            transfers.values().filter_map(|info| info.source.clone()).collect(),
+        )
+    };
+
+    let mut stored_files = stored_files
+        .lock()
+        .map_err(|err| {
+            error!("Failed to get stored files mutex: {:?}", err);
+            err
+        })
+        .unwrap();
+    let mut budget = budget
+        .lock()
+        .map_err(|err| {
+            error!("Failed to get state budget mutex: {:?}", err);
+            err
+        })
+        .unwrap();
+
+    // The protocol adds entries of its own, so pick those up before choosing what to evict
+    budget.sync(
+        stored_files
+            .iter()
+            .map(|(key, entry)| (key.as_str(), state::entry_size(key, entry))),
+    );
+
+    let keep: HashSet<String> = stored_files
+        .iter()
+        .filter(|(key, (_, hash, _, _))| sources.contains(*key) || hashes.contains(hash))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in budget.evict(&keep) {
+        info!("Evicting {} to stay within max_state_memory", key);
+        stored_files.remove(&key);
+    }
+
+    usage.measure(&stored_files);
+    debug!("Stored files state using ~{} bytes", budget.used());
+}
+
 // We need this in this lib.rs file so we can build integration tests
 pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
+    recv_loop_with_usage(config, StateUsage::new())
+}
+
+/// Run the service, keeping `usage` up to date with the memory held in transfer state
+pub fn recv_loop_with_usage(
+    config: &ServiceConfig,
+    usage: StateUsage,
+) -> Result<(), failure::Error> {
     // Get and bind our UDP listening socket
     let host = config
         .hosturl()
@@ -41,6 +215,22 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         .next()
         .ok_or_else(|| failure::format_err!("Failed to parse service IP address"))?;
 
+    // Get the directory which relative paths in requests are resolved against. Without
+    // one they'd resolve against whatever directory the daemon was started from.
+    let working_dir = match config.get("working_dir") {
+        Some(dir) => {
+            let dir = dir
+                .as_str()
+                .ok_or_else(|| failure::format_err!("working_dir must be a path"))?;
+            let resolved = std::fs::canonicalize(dir).map_err(|err| {
+                failure::format_err!("Failed to resolve working dir {}: {}", dir, err)
+            })?;
+            info!("Working directory {}", resolved.display());
+            Some(resolved)
+        }
+        None => None,
+    };
+
     // Get the storage directory prefix that we'll be using for our
     // temporary/intermediate storage location
     let prefix = match config.get("storage_dir") {
@@ -82,6 +272,32 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         None => "127.0.0.1".to_owned(),
     };
 
+    // Refuse to run if the downlink destination isn't one we're permitted to send to.
+    // The destination is fixed for the lifetime of the service, so checking it here
+    // covers every transfer we'll go on to make.
+    if let Some(allowed) = config.get("allowed_downlinks") {
+        let allowed = allowed
+            .as_array()
+            .ok_or_else(|| {
+                failure::format_err!("allowed_downlinks must be a list of addresses")
+            })?
+            .iter()
+            .map(|entry| {
+                entry.as_str().map(|entry| entry.to_owned()).ok_or_else(|| {
+                    failure::format_err!("Invalid allowed_downlinks entry: {}", entry)
+                })
+            })
+            .collect::<Result<Vec<String>, _>>()?;
+
+        let addr = format!("{}:{}", downlink_ip, downlink_port);
+        if !allowed
+            .iter()
+            .any(|entry| *entry == addr || *entry == downlink_ip)
+        {
+            return Err(ServiceError::DownlinkNotAllowed { addr }.into());
+        }
+    }
+
     // Get the inter chunk delay value
     let inter_chunk_delay = config
         .get("inter_chunk_delay")
@@ -94,6 +310,79 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         .and_then(|chunks| chunks.as_integer())
         .map(|chunks| chunks as u32);
 
+    // Get the setuid, setgid and sticky bits which uploads may ask for. None of them
+    // are allowed unless they're listed here.
+    let allowed_special_mode_bits = match config.get("allowed_special_mode_bits") {
+        Some(bits) => {
+            let bits = bits.as_integer().ok_or_else(|| {
+                failure::format_err!("allowed_special_mode_bits must be an integer")
+            })?;
+            if bits & !i64::from(SPECIAL_MODE_BITS) != 0 {
+                return Err(failure::format_err!(
+                    "allowed_special_mode_bits may only contain bits of {:o}",
+                    SPECIAL_MODE_BITS
+                ));
+            }
+            bits as u32
+        }
+        None => 0,
+    };
+
+    // Get the templates which upload target paths must match, if they're restricted
+    let target_path_templates = match config.get("target_path_templates") {
+        Some(templates) => Some(
+            templates
+                .as_array()
+                .ok_or_else(|| {
+                    failure::format_err!("target_path_templates must be a list of paths")
+                })?
+                .iter()
+                .map(|entry| {
+                    entry.as_str().map(|entry| entry.to_owned()).ok_or_else(|| {
+                        failure::format_err!("Invalid target_path_templates entry: {}", entry)
+                    })
+                })
+                .collect::<Result<Vec<String>, _>>()?,
+        ),
+        None => None,
+    };
+
+    // Get the approximate cap on memory used by transfer state
+    let max_state_memory = config
+        .get("max_state_memory")
+        .and_then(|val| val.as_integer())
+        .map(|bytes| bytes as usize);
+
+    // Get the limit on how many channels may be processed at once
+    let max_concurrent_transfers = match config
+        .get("max_concurrent_transfers")
+        .and_then(|val| val.as_integer())
+    {
+        Some(num) if num < 1 => {
+            return Err(failure::format_err!(
+                "max_concurrent_transfers must be at least 1, got {}",
+                num
+            ))
+        }
+        Some(num) => Some(num as usize),
+        None => None,
+    };
+
+    // Get how many channels may wait for a free slot before new ones are refused
+    let max_queued_transfers = match config
+        .get("max_queued_transfers")
+        .and_then(|val| val.as_integer())
+    {
+        Some(num) if num < 0 => {
+            return Err(failure::format_err!(
+                "max_queued_transfers must not be negative, got {}",
+                num
+            ))
+        }
+        Some(num) => num as usize,
+        None => max_concurrent_transfers.unwrap_or(0),
+    };
+
     let num_threads = config
         .get("num_threads")
         .and_then(|i| i.as_integer())
@@ -105,6 +394,8 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
     info!("Transfer Chunk {}", transfer_chunk_size);
     info!("Hash Chunk Size {}", hash_chunk_size);
 
+    let downlink = format!("{}:{}", downlink_ip, downlink_port);
+
     let f_config = FileProtocolConfig::new(
         prefix.clone(),
         transfer_chunk_size.clone(),
@@ -122,20 +413,201 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)))
         .unwrap_or(Duration::from_secs(2));
 
-    // Setup map of channel IDs to thread channels
-    let raw_threads: HashMap<u32, Sender<Vec<u8>>> = HashMap::new();
+    // Get the grace period during which late messages for a completed channel are dropped
+    let completed_grace = config
+        .get("completed_channel_grace")
+        .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)))
+        .unwrap_or(Duration::from_secs(30));
+
+    // Get how long a queued channel may wait for a slot before it's told to retry later
+    let max_queue_wait = config
+        .get("max_queue_wait")
+        .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)))
+        .unwrap_or(timeout);
+
+    // Get the hard cap on how long any single channel may stay open
+    let max_channel_lifetime = config
+        .get("max_channel_lifetime")
+        .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)));
+
+    // Get the age after which abandoned temp storage is garbage collected
+    let cleanup_older_than = config
+        .get("cleanup_older_than")
+        .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)));
+
+    // Get how often to check for abandoned temp storage
+    let cleanup_interval = config
+        .get("cleanup_interval")
+        .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)))
+        .unwrap_or(Duration::from_secs(60));
+
+    // Setup map of channel IDs to thread channels and the time each channel was opened
+    let raw_threads: Threads = HashMap::new();
     // Create thread sharable wrapper
     let threads = Arc::new(Mutex::new(raw_threads));
 
-    let stored_files = Arc::new(Mutex::new(HashMap::new()));
+    let completed: Arc<Mutex<Completed>> = Arc::new(Mutex::new(HashMap::new()));
+
+    // Setup map of open channel IDs to the transfer each one is carrying
+    let transfers: Arc<Mutex<Transfers>> = Arc::new(Mutex::new(HashMap::new()));
+
+    // Setup count of running channel handlers and the channels waiting for one
+    let transfer_slots = Arc::new(Mutex::new(TransferSlots {
+        active: 0,
+        queued: VecDeque::new(),
+    }));
+
+    if let Some(lifetime) = max_channel_lifetime {
+        let reaper_threads = threads.clone();
+        let reaper_completed = completed.clone();
+        let reaper_slots = transfer_slots.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+
+            // Queued channels haven't started yet, so leave them be. Holding the slots
+            // lock also stops a channel from starting part way through.
+            let slots = reaper_slots
+                .lock()
+                .map_err(|err| {
+                    error!("Failed to get transfer slots mutex: {:?}", err);
+                    err
+                })
+                .unwrap();
+            let queued: HashSet<u32> = slots
+                .queued
+                .iter()
+                .map(|(channel_id, _, _)| *channel_id)
+                .collect();
+
+            // Dropping a channel's sender disconnects its receiver, so the handler
+            // thread bails out the next time it waits for a message
+            let mut threads = reaper_threads
+                .lock()
+                .map_err(|err| {
+                    error!("Failed to get threads mutex: {:?}", err);
+                    err
+                })
+                .unwrap();
+            let expired: Vec<u32> = threads
+                .iter()
+                .filter(|(channel_id, (_, opened))| {
+                    !queued.contains(*channel_id) && opened.elapsed() > lifetime
+                })
+                .map(|(channel_id, _)| *channel_id)
+                .collect();
+
+            for channel_id in expired {
+                warn!(
+                    "Channel {} exceeded max lifetime of {}s, aborting",
+                    channel_id,
+                    lifetime.as_secs()
+                );
+                threads.remove(&channel_id);
+
+                // Treat stragglers for the aborted channel like those of a completed one
+                reaper_completed
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get completed mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .insert(channel_id, (Instant::now(), None));
+            }
+        });
+    }
+
+    let stored_files: Arc<Mutex<StoredFiles>> = Arc::new(Mutex::new(HashMap::new()));
     let stored_files_clone = Arc::clone(&stored_files);
 
+    // Setup tracking of how recently each stored file was used, so that the idle ones
+    // can be evicted if the map grows past max_state_memory
+    let state_budget = Arc::new(Mutex::new(StateBudget::new(max_state_memory)));
+    let watcher_budget = Arc::clone(&state_budget);
+    let watcher_transfers = Arc::clone(&transfers);
+    let watcher_usage = usage.clone();
+    let gc_stored_files = Arc::clone(&stored_files);
+    let gc_budget = Arc::clone(&state_budget);
+    let gc_transfers = Arc::clone(&transfers);
+    let gc_usage = usage.clone();
+    let gc_prefix = prefix.clone();
+
+    if max_concurrent_transfers.is_some() {
+        let busy_socket = host_socket.try_clone()?;
+        let busy_downlink = downlink.clone();
+        let busy_threads = threads.clone();
+        let busy_completed = completed.clone();
+        let busy_transfers = transfers.clone();
+        let busy_slots = transfer_slots.clone();
+        let busy_usage = usage.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+
+            // Tell queued channels which have waited too long to retry later, rather
+            // than leaving their clients to time out
+            let expired: VecDeque<(u32, Receiver<Vec<u8>>, Instant)> = {
+                let mut slots = busy_slots
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get transfer slots mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap();
+                let (expired, waiting) = slots
+                    .queued
+                    .drain(..)
+                    .partition(|(_, _, queued)| queued.elapsed() > max_queue_wait);
+                slots.queued = waiting;
+                expired
+            };
+
+            for (channel_id, receiver, _) in expired {
+                busy_threads
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get threads mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .remove(&channel_id);
+                busy_transfers
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get transfers mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .remove(&channel_id);
+
+                // Treat stragglers for the dropped channel like those of a completed one
+                busy_completed
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get completed mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .insert(channel_id, (Instant::now(), None));
+
+                for message in receiver.try_iter() {
+                    busy_usage.release(message.len());
+                }
+                refuse_channel(
+                    &busy_socket,
+                    &busy_downlink,
+                    channel_id,
+                    "Service busy, retry later",
+                );
+            }
+        });
+    }
+
     let protocol_loop = thread::spawn(move || {
 
 
         loop {
             let mut buf = vec![0; hash_chunk_size];
-            let (_source, first_message) = match host_socket.recv_from(&mut buf) {
+            let (_source, mut first_message) = match host_socket.recv_from(&mut buf) {
                 Ok((size, source)) => {
                     buf.truncate(size);
                     (source, buf)
@@ -158,6 +630,98 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                     continue;
                 }
             };
+
+            // Straggler packets (duplicate chunks, repeated ACKs) can trail in after a
+            // channel has finished. Drop them rather than spinning up a fresh handler
+            // for a transfer which is already done.
+            let finished = completed
+                .lock()
+                .map_err(|err| {
+                    error!("Failed to get completed mutex: {:?}", err);
+                    err
+                })
+                .unwrap()
+                .get(&channel_id)
+                .cloned();
+            if let Some((finished, info)) = finished {
+                if finished.elapsed() <= completed_grace {
+                    debug!("Dropping late message for completed channel {}", channel_id);
+
+                    // Re-send the completion ACK so that the sender stops retrying
+                    let ack = info.and_then(|info| transfer::completion_ack(channel_id, &info));
+                    if let Some(ack) = ack {
+                        if let Err(e) = host_socket.send_to(&ack, &downlink) {
+                            warn!("Failed to re-send ACK for channel {}: {:?}", channel_id, e);
+                        }
+                    }
+                    continue;
+                }
+
+                info!(
+                    "Received message for channel {} after its grace period, treating as new",
+                    channel_id
+                );
+                completed
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get completed mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .remove(&channel_id);
+            }
+
+            let mut message = bincode::deserialize::<Message>(&first_message).ok();
+
+            // Point relative paths in requests into the working directory before the
+            // protocol, or any of the checks below, see them
+            if let (Some(working_dir), Some(request)) = (&working_dir, &mut message) {
+                if paths::resolve_request(working_dir, request) {
+                    match bincode::serialize(request) {
+                        Ok(resolved) => first_message = resolved,
+                        Err(e) => {
+                            warn!("Failed to re-encode request for channel {}: {}", channel_id, e);
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            // Check where an upload wants to land, and the mode it wants, before the
+            // protocol gets to act on its export request
+            if let Some(Message::ReqReceive { path, mode, .. }) = &message {
+                // Only let uploads land on paths matching one of the configured templates
+                let path_allowed = match &target_path_templates {
+                    Some(templates) => templates
+                        .iter()
+                        .any(|template| templates::path_matches(template, path)),
+                    None => true,
+                };
+
+                let mode = mode.unwrap_or(0);
+                let rejection = if !path_allowed {
+                    Some(format!("Target path {} is not allowed", path))
+                } else if mode & SPECIAL_MODE_BITS & !allowed_special_mode_bits != 0 {
+                    Some(format!("Mode {:o} is not allowed", mode))
+                } else {
+                    None
+                };
+
+                if let Some(reason) = rejection {
+                    refuse_channel(&host_socket, &downlink, channel_id, &reason);
+
+                    // Give up on the channel, so its handler stops waiting for chunks
+                    threads
+                        .lock()
+                        .map_err(|err| {
+                            error!("Failed to get threads mutex: {:?}", err);
+                            err
+                        })
+                        .unwrap()
+                        .remove(&channel_id);
+                    continue;
+                }
+            }
     
             if !threads
                 .lock()
@@ -168,6 +732,46 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                 .unwrap()
                 .contains_key(&channel_id)
             {
+                // Don't take on any more work while transfer state is over its budget, once
+                // evicting any idle prepared files has had a chance to bring it back under
+                if let Some(limit) = max_state_memory {
+                    reclaim_state(&stored_files, &state_budget, &transfers, &usage);
+                    if usage.bytes() > limit {
+                        refuse_channel(
+                            &host_socket,
+                            &downlink,
+                            channel_id,
+                            "Transfer state over max_state_memory, retry later",
+                        );
+                        continue;
+                    }
+                }
+
+                // Only start a new handler if we have a free slot. Otherwise the channel's
+                // messages queue up in its receiver until an existing handler frees up,
+                // unless the queue is already full.
+                let mut slots = transfer_slots
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get transfer slots mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap();
+                let at_capacity = match max_concurrent_transfers {
+                    Some(max) => slots.active >= max,
+                    None => false,
+                };
+                if at_capacity && slots.queued.len() >= max_queued_transfers {
+                    drop(slots);
+                    refuse_channel(
+                        &host_socket,
+                        &downlink,
+                        channel_id,
+                        "Service busy, retry later",
+                    );
+                    continue;
+                }
+
                 let (sender, receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) =
                     mpsc::channel();
     
@@ -178,58 +782,159 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                         err
                     })
                     .unwrap()
-                    .insert(channel_id, sender.clone());
-    
-                // Break the processing work off into its own thread so we can
-                // listen for requests from other clients
-                let shared_threads = threads.clone();
-                let downlink_ip_ref = downlink_ip.to_owned();
-                let clone_stored_files = Arc::clone(&stored_files);
-                thread::spawn(move || {
-                    debug!("Starting new thread for channel {}", channel_id);
-                    let state = State::Holding {
-                        count: 0,
-                        prev_state: Box::new(State::Done),
-                    };
-                    
-                    // Set up the file system processor with the reply socket information
-                    let f_protocol = FileProtocol::new(
-                        &format!("{}:{}", host_ref, 0),
-                        &format!("{}:{}", downlink_ip_ref, downlink_port),
-                        config_ref,
-                        num_threads,
-                        clone_stored_files,
+                    .insert(channel_id, (sender.clone(), Instant::now()));
+
+                if at_capacity {
+                    info!(
+                        "Max concurrent transfers reached, queueing channel {}",
+                        channel_id
                     );
-    
-                    // Listen, process, and react to the remaining messages in the
-                    // requested operation
-                    if let Err(e) = f_protocol.message_engine(
-                        |d| match receiver.recv_timeout(d) {
-                            Ok(v) => Ok(v),
-                            Err(RecvTimeoutError::Timeout) => Err(ProtocolError::ReceiveTimeout),
-                            Err(e) => Err(ProtocolError::ReceiveError {
-                                err: format!("Error {:?}", e),
-                            }),
-                        },
-                        timeout_ref,
-                        &state,
-                    ) {
-                        warn!("Encountered errors while processing transaction: {}", e);
+                    slots.queued.push_back((channel_id, receiver, Instant::now()));
+                } else {
+                    slots.active += 1;
+                    let mut slot = SlotGuard {
+                        slots: transfer_slots.clone(),
+                        held: true,
+                    };
+
+                    // Break the processing work off into its own thread so we can
+                    // listen for requests from other clients
+                    let shared_threads = threads.clone();
+                    let shared_completed = completed.clone();
+                    let shared_transfers = transfers.clone();
+                    let downlink_ip_ref = downlink_ip.to_owned();
+                    let clone_stored_files = Arc::clone(&stored_files);
+                    let shared_budget = Arc::clone(&state_budget);
+                    let shared_usage = usage.clone();
+                    thread::spawn(move || {
+                        let mut next = Some((channel_id, receiver));
+                        while let Some((channel_id, receiver)) = next {
+                            debug!("Starting new thread for channel {}", channel_id);
+                            let state = State::Holding {
+                                count: 0,
+                                prev_state: Box::new(State::Done),
+                            };
+
+                            // Set up the file system processor with the reply socket information
+                            let f_protocol = FileProtocol::new(
+                                &format!("{}:{}", host_ref, 0),
+                                &format!("{}:{}", downlink_ip_ref, downlink_port),
+                                config_ref.clone(),
+                                num_threads,
+                                Arc::clone(&clone_stored_files),
+                            );
+
+                            // Listen, process, and react to the remaining messages in the
+                            // requested operation
+                            let result = f_protocol.message_engine(
+                                |d| match receiver.recv_timeout(d) {
+                                    Ok(v) => {
+                                        shared_usage.release(v.len());
+                                        Ok(v)
+                                    }
+                                    Err(RecvTimeoutError::Timeout) => Err(ProtocolError::ReceiveTimeout),
+                                    Err(e) => Err(ProtocolError::ReceiveError {
+                                        err: format!("Error {:?}", e),
+                                    }),
+                                },
+                                timeout_ref,
+                                &state,
+                            );
+
+                            let info = shared_transfers
+                                .lock()
+                                .map_err(|err| {
+                                    error!("Failed to get transfers mutex: {:?}", err);
+                                    err
+                                })
+                                .unwrap()
+                                .remove(&channel_id);
+
+                            match result {
+                                Ok(()) => {
+                                    // Remember that we finished so that any stragglers are dropped
+                                    let mut completed = shared_completed
+                                        .lock()
+                                        .map_err(|err| {
+                                            error!("Failed to get completed mutex: {:?}", err);
+                                            err
+                                        })
+                                        .unwrap();
+                                    completed.retain(|_, (finished, _)| finished.elapsed() <= completed_grace);
+                                    completed.insert(channel_id, (Instant::now(), info));
+                                }
+                                Err(e) => warn!("Encountered errors while processing transaction: {}", e),
+                            }
+
+                            // Remove ourselves from threads list if we are finished
+                            shared_threads
+                                .lock()
+                                .map_err(|err| {
+                                    error!("Failed to get threads mutex: {:?}", err);
+                                    err
+                                })
+                                .unwrap()
+                                .remove(&channel_id);
+
+                            // Nothing more can be queued for us now, so release whatever is left,
+                            // and give up any prepared files nobody else has a use for
+                            for message in receiver.try_iter() {
+                                shared_usage.release(message.len());
+                            }
+                            reclaim_state(
+                                &clone_stored_files,
+                                &shared_budget,
+                                &shared_transfers,
+                                &shared_usage,
+                            );
+
+                            // Hand our slot to the next queued channel, or give it up
+                            next = slot.next_queued(&shared_threads);
+                        }
+                    });
+                }
+            }
+
+            // A download of a prepared file counts as using it
+            if let Some(Message::ReqTransmit { path, .. }) = &message {
+                state_budget
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get state budget mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .touch(path);
+            }
+
+            // Note what the channel is carrying as its messages tell us
+            if let Some(message) = &message {
+                let mut transfers = transfers
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get transfers mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap();
+                let info = transfers.entry(channel_id).or_default();
+                info.update(message);
+
+                // A chunk numbered past the count the upload declared can't belong to it,
+                // so drop it before it reaches the protocol's chunk tracking
+                if let (Message::ReceiveChunk { chunk_num, .. }, Some(num_chunks)) =
+                    (message, info.num_chunks)
+                {
+                    if *chunk_num >= num_chunks {
+                        warn!(
+                            "Dropping chunk {} for channel {}, which only has {} chunks",
+                            chunk_num, channel_id, num_chunks
+                        );
+                        continue;
                     }
-    
-                    // Remove ourselves from threads list if we are finished
-                    shared_threads
-                        .lock()
-                        .map_err(|err| {
-                            error!("Failed to get threads mutex: {:?}", err);
-                            err
-                        })
-                        .unwrap()
-                        .remove(&channel_id);
-                });
+                }
             }
     
-            if let Some(sender) = threads
+            if let Some((sender, _)) = threads
                 .lock()
                 .map_err(|err| {
                     error!("Failed to get threads mutex: {:?}", err);
@@ -238,7 +943,11 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                 .unwrap()
                 .get(&channel_id)
             {
+                // Count the message before the channel can take it
+                let size = first_message.len();
+                usage.buffer(size);
                 if let Err(e) = sender.send(first_message) {
+                    usage.release(size);
                     warn!("Error when sending to channel {}: {:?}", channel_id, e);
                 }
             }
@@ -287,10 +996,21 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                             // A new file was created, initialize it
                             match file_protocol::storage::initialize_file(&prefix.as_ref().unwrap(), path.to_str().unwrap(), transfer_chunk_size, hash_chunk_size) {
                                 Ok((file_name, hash, num_chunks, mode)) => {
-                                    stored_files_clone.lock().unwrap().insert(
-                                        path.to_str().unwrap().to_string(),
-                                        (file_name, hash, num_chunks, mode),
-                                    );
+                                    let key = path.to_str().unwrap().to_string();
+                                    let entry = (file_name, hash, num_chunks, mode);
+                                    let size = state::entry_size(&key, &entry);
+                                    // An entry which could never fit would only push everything else out
+                                    if matches!(max_state_memory, Some(limit) if size > limit) {
+                                        warn!("Not preparing {}: too large for max_state_memory", key);
+                                        continue;
+                                    }
+                                    {
+                                        // A re-created file counts as freshly used
+                                        let mut stored_files = stored_files_clone.lock().unwrap();
+                                        watcher_budget.lock().unwrap().insert(&key, size);
+                                        stored_files.insert(key, entry);
+                                    }
+                                    reclaim_state(&stored_files_clone, &watcher_budget, &watcher_transfers, &watcher_usage);
                                 },
                                 Err(e) => println!("Error initializing file: {:?}", e),
                             }
@@ -304,8 +1024,121 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         }        
     });
 
+    if let (Some(older_than), Some(storage_dir)) = (cleanup_older_than, gc_prefix) {
+        thread::spawn(move || loop {
+            thread::sleep(cleanup_interval);
+
+            // Never remove temp storage for an open channel's transfer. Prepared files
+            // only count while a channel is downloading them, so idle ones can go.
+            let (hashes, sources): (HashSet<String>, HashSet<String>) = {
+                let transfers = gc_transfers
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get transfers mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap();
+                (
+                    transfers.values().filter_map(|info| info.hash.clone()).collect(),
+                    transfers.values().filter_map(|info| info.source.clone()).collect(),
+                )
+            };
+            let mut active = hashes;
+            active.extend(
+                gc_stored_files
+                    .lock()
+                    .map_err(|err| {
+                        error!("Failed to get stored files mutex: {:?}", err);
+                        err
+                    })
+                    .unwrap()
+                    .iter()
+                    .filter(|(key, _)| sources.contains(*key))
+                    .map(|(_, (_, hash, _, _))| hash.clone()),
+            );
+
+            match gc(&storage_dir, older_than, &active) {
+                Ok((0, _)) => {}
+                Ok((dirs, bytes)) => info!(
+                    "Reclaimed {} stale temp storage directories ({} bytes)",
+                    dirs, bytes
+                ),
+                Err(err) => warn!("Failed to clean up temp storage: {}", err),
+            }
+
+            // A prepared file whose temp storage is gone can't be sent any more, so
+            // forget it too
+            gc_stored_files
+                .lock()
+                .map_err(|err| {
+                    error!("Failed to get stored files mutex: {:?}", err);
+                    err
+                })
+                .unwrap()
+                .retain(|_, (_, hash, _, _)| {
+                    active.contains(hash)
+                        || Path::new(&storage_dir).join("storage").join(hash).exists()
+                });
+            reclaim_state(&gc_stored_files, &gc_budget, &gc_transfers, &gc_usage);
+        });
+    }
+
     protocol_loop.join().unwrap();
     file_initialise_loop.join().unwrap();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A handler which panics still gives its slot back
+    #[test]
+    fn slot_released_on_panic() {
+        let slots = Arc::new(Mutex::new(TransferSlots {
+            active: 1,
+            queued: VecDeque::new(),
+        }));
+        let slot = SlotGuard {
+            slots: slots.clone(),
+            held: true,
+        };
+
+        let handler = thread::spawn(move || {
+            let _slot = slot;
+            panic!("Handler failed");
+        });
+        assert!(handler.join().is_err());
+
+        assert_eq!(slots.lock().unwrap().active, 0);
+    }
+
+    // A slot is given up exactly once when there's nothing left in the queue
+    #[test]
+    fn slot_released_when_queue_empty() {
+        let slots = Arc::new(Mutex::new(TransferSlots {
+            active: 1,
+            queued: VecDeque::new(),
+        }));
+        let threads = Mutex::new(HashMap::new());
+
+        let (_sender, receiver) = mpsc::channel();
+        slots
+            .lock()
+            .unwrap()
+            .queued
+            .push_back((2, receiver, Instant::now()));
+
+        let mut slot = SlotGuard {
+            slots: slots.clone(),
+            held: true,
+        };
+        assert_eq!(slot.next_queued(&threads).map(|(id, _)| id), Some(2));
+        assert_eq!(slots.lock().unwrap().active, 1);
+
+        assert!(slot.next_queued(&threads).is_none());
+        drop(slot);
+        assert_eq!(slots.lock().unwrap().active, 0);
+    }
+}
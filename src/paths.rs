@@ -0,0 +1,53 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use file_protocol::Message;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Rewrite a relative path in an upload or download request so that it points into
+// `working_dir`, returning whether the request was changed
+pub(crate) fn resolve_request(working_dir: &Path, request: &mut Message) -> bool {
+    let path = match request {
+        Message::ReqReceive { path, .. } | Message::ReqTransmit { path, .. } => path,
+        _ => return false,
+    };
+
+    if Path::new(path.as_str()).is_absolute() {
+        return false;
+    }
+
+    *path = resolve(working_dir, path).to_string_lossy().into_owned();
+    true
+}
+
+// Join a relative path onto `working_dir` and canonicalize it, so that any `..`
+// components are gone before the path is checked. Upload targets don't exist yet,
+// so those are canonicalized through their parent directory instead.
+fn resolve(working_dir: &Path, path: &str) -> PathBuf {
+    let joined = working_dir.join(path);
+    if let Ok(resolved) = fs::canonicalize(&joined) {
+        return resolved;
+    }
+
+    match (joined.parent(), joined.file_name()) {
+        (Some(parent), Some(name)) => match fs::canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => joined,
+        },
+        _ => joined,
+    }
+}
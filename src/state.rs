@@ -0,0 +1,259 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Shared, approximate count of the bytes held in a running service's transfer state
+///
+/// This covers every entry in the shared stored files map, including those added by
+/// the protocol itself, along with the messages buffered for each channel until its
+/// handler gets to them. Cloned handles all read the same count, so keep one before
+/// passing it to `recv_loop_with_usage`.
+#[derive(Clone, Debug, Default)]
+pub struct StateUsage {
+    stored: Arc<AtomicUsize>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl StateUsage {
+    /// Create a new, empty usage count
+    pub fn new() -> Self {
+        StateUsage::default()
+    }
+
+    /// Approximate number of bytes currently held
+    pub fn bytes(&self) -> usize {
+        self.stored.load(Ordering::SeqCst) + self.buffered.load(Ordering::SeqCst)
+    }
+
+    // Re-measure the stored files map
+    pub(crate) fn measure(&self, stored_files: &HashMap<String, (String, String, u32, u32)>) {
+        let size = stored_files
+            .iter()
+            .map(|(key, entry)| entry_size(key, entry))
+            .sum();
+        self.stored.store(size, Ordering::SeqCst);
+    }
+
+    // Count a message queued for a channel
+    pub(crate) fn buffer(&self, bytes: usize) {
+        self.buffered.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    // Stop counting a message once its channel has taken it
+    pub(crate) fn release(&self, bytes: usize) {
+        self.buffered.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+// Approximate size of a single stored files entry
+pub(crate) fn entry_size(key: &str, entry: &(String, String, u32, u32)) -> usize {
+    key.len() + entry.0.len() + entry.1.len() + mem::size_of_val(entry)
+}
+
+// Approximate accounting of the memory held in the shared stored files map
+//
+// Entries are kept in the order they were last used, so when a limit is set the
+// least recently used entries are the first to be evicted.
+pub(crate) struct StateBudget {
+    limit: Option<usize>,
+    entries: VecDeque<(String, usize)>,
+    used: usize,
+}
+
+impl StateBudget {
+    // Create a new budget, optionally capped at `limit` bytes
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        StateBudget {
+            limit,
+            entries: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Approximate number of bytes currently held by tracked entries
+    pub(crate) fn used(&self) -> usize {
+        self.used
+    }
+
+    // Track an entry of `size` bytes under `key` as the most recently used
+    pub(crate) fn insert(&mut self, key: &str, size: usize) {
+        // Replacing an existing entry releases whatever it was holding
+        self.remove(key);
+
+        self.entries.push_back((key.to_owned(), size));
+        self.used += size;
+    }
+
+    // Mark the entry under `key`, if we're tracking one, as the most recently used
+    pub(crate) fn touch(&mut self, key: &str) {
+        if let Some(index) = self.entries.iter().position(|(entry, _)| entry == key) {
+            if let Some(entry) = self.entries.remove(index) {
+                self.entries.push_back(entry);
+            }
+        }
+    }
+
+    // Bring the tracked entries in line with `current`, the full contents of the map
+    //
+    // Entries which have since been removed are forgotten, and entries added by
+    // someone else are tracked as the most recently used.
+    pub(crate) fn sync<'a, I>(&mut self, current: I)
+    where
+        I: IntoIterator<Item = (&'a str, usize)>,
+    {
+        let mut current: HashMap<&str, usize> = current.into_iter().collect();
+
+        // Keep the order of the entries we already know about, picking up their sizes
+        let mut entries: VecDeque<(String, usize)> = mem::take(&mut self.entries)
+            .into_iter()
+            .filter_map(|(key, _)| current.remove(key.as_str()).map(|size| (key, size)))
+            .collect();
+        entries.extend(current.into_iter().map(|(key, size)| (key.to_owned(), size)));
+
+        self.used = entries.iter().map(|(_, size)| size).sum();
+        self.entries = entries;
+    }
+
+    // Stop tracking the entry stored under `key`
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(index) = self.entries.iter().position(|(entry, _)| entry == key) {
+            if let Some((_, size)) = self.entries.remove(index) {
+                self.used -= size;
+            }
+        }
+    }
+
+    // Stop tracking the least recently used entries, other than those in `keep`, until
+    // we're back within the limit. Returns the keys which must be evicted from the map.
+    pub(crate) fn evict(&mut self, keep: &HashSet<String>) -> Vec<String> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return vec![],
+        };
+
+        let mut evicted = vec![];
+        let mut index = 0;
+        while self.used > limit && index < self.entries.len() {
+            if keep.contains(&self.entries[index].0) {
+                index += 1;
+                continue;
+            }
+
+            if let Some((key, size)) = self.entries.remove(index) {
+                self.used -= size;
+                evicted.push(key);
+            }
+        }
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Push state past the limit and check that the least recently used entries go first
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut budget = StateBudget::new(Some(100));
+
+        budget.insert("first", 40);
+        budget.insert("second", 40);
+        budget.insert("third", 40);
+        assert_eq!(budget.used(), 120);
+
+        // Using the oldest entry saves it from being evicted
+        budget.touch("first");
+        assert_eq!(budget.evict(&HashSet::new()), vec!["second".to_owned()]);
+        assert_eq!(budget.used(), 80);
+
+        budget.insert("fourth", 90);
+        assert_eq!(
+            budget.evict(&HashSet::new()),
+            vec!["third".to_owned(), "first".to_owned()]
+        );
+        assert_eq!(budget.used(), 90);
+    }
+
+    // Entries in use are never evicted, even if that leaves us over the limit
+    #[test]
+    fn evict_skips_kept_entries() {
+        let mut budget = StateBudget::new(Some(50));
+
+        budget.insert("busy", 40);
+        budget.insert("idle", 40);
+        budget.insert("also_busy", 40);
+
+        let keep: HashSet<String> = vec!["busy".to_owned(), "also_busy".to_owned()]
+            .into_iter()
+            .collect();
+        assert_eq!(budget.evict(&keep), vec!["idle".to_owned()]);
+        assert_eq!(budget.used(), 80);
+
+        // Once they're no longer in use, they can go too
+        assert_eq!(budget.evict(&HashSet::new()), vec!["busy".to_owned()]);
+        assert_eq!(budget.used(), 40);
+    }
+
+    // Nothing is evicted while within the limit, or when there is no limit
+    #[test]
+    fn evicts_nothing_within_limit() {
+        let mut budget = StateBudget::new(Some(100));
+        budget.insert("file", 100);
+        assert!(budget.evict(&HashSet::new()).is_empty());
+
+        let mut budget = StateBudget::new(None);
+        budget.insert("file", 1000);
+        assert!(budget.evict(&HashSet::new()).is_empty());
+        assert_eq!(budget.used(), 1000);
+    }
+
+    // Replacing or removing an entry releases its old size
+    #[test]
+    fn tracks_replacement() {
+        let mut budget = StateBudget::new(None);
+
+        budget.insert("file", 50);
+        budget.insert("file", 20);
+        assert_eq!(budget.used(), 20);
+
+        budget.remove("file");
+        assert_eq!(budget.used(), 0);
+    }
+
+    // Entries added by someone else are picked up as the most recently used, and
+    // removed ones are dropped
+    #[test]
+    fn syncs_with_map() {
+        let mut budget = StateBudget::new(Some(100));
+
+        budget.insert("ours", 30);
+        budget.sync(vec![("ours", 30), ("theirs", 40)]);
+        assert_eq!(budget.used(), 70);
+
+        // The entry we added is still the least recently used, so it goes first
+        budget.insert("new", 40);
+        assert_eq!(budget.evict(&HashSet::new()), vec!["ours".to_owned()]);
+
+        budget.sync(vec![("new", 45)]);
+        assert_eq!(budget.used(), 45);
+    }
+}
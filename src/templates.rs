@@ -0,0 +1,37 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Check whether a path matches a template such as `/var/log/{date}/{name}`
+//
+// Each `{placeholder}` stands in for exactly one path component, and every other
+// component must match the template exactly. Placeholders never match `.` or `..`,
+// so a matching path can't climb out of the directories the template names.
+pub(crate) fn path_matches(template: &str, path: &str) -> bool {
+    let template: Vec<&str> = template.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+
+    template.len() == path.len()
+        && template
+            .iter()
+            .zip(path.iter())
+            .all(|(expected, actual)| {
+                if expected.starts_with('{') && expected.ends_with('}') && expected.len() > 2 {
+                    !actual.is_empty() && *actual != "." && *actual != ".."
+                } else {
+                    expected == actual
+                }
+            })
+}
@@ -0,0 +1,78 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use file_protocol::Message;
+use log::warn;
+
+// What the receive loop has learned about a channel's transfer from its messages
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TransferInfo {
+    pub(crate) hash: Option<String>,
+    // Only known for uploads, which announce it in their metadata
+    pub(crate) num_chunks: Option<u32>,
+    // Only known for downloads, which name the file they want
+    pub(crate) source: Option<String>,
+}
+
+impl TransferInfo {
+    // Pick up whatever a message says about the transfer that we don't know yet
+    pub(crate) fn update(&mut self, message: &Message) {
+        match message {
+            Message::Metadata {
+                hash, num_chunks, ..
+            } => {
+                self.hash.get_or_insert_with(|| hash.clone());
+                self.num_chunks.get_or_insert(*num_chunks);
+            }
+            Message::Sync { hash, .. }
+            | Message::ReceiveChunk { hash, .. }
+            | Message::ACK { hash, .. }
+            | Message::NAK { hash, .. }
+            | Message::ReqReceive { hash, .. } => {
+                self.hash.get_or_insert_with(|| hash.clone());
+            }
+            Message::ReqTransmit { path, .. } => {
+                self.source.get_or_insert_with(|| path.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+// Build the ACK which tells a sender that its upload has already been received
+pub(crate) fn completion_ack(channel_id: u32, info: &TransferInfo) -> Option<Vec<u8>> {
+    let ack = Message::ACK {
+        channel_id,
+        hash: info.hash.clone()?,
+        num_chunks: Some(info.num_chunks?),
+    };
+
+    bincode::serialize(&ack)
+        .map_err(|err| warn!("Failed to encode ACK for channel {}: {}", channel_id, err))
+        .ok()
+}
+
+// Build the failure which tells a client that its channel won't be processed
+pub(crate) fn failure_message(channel_id: u32, error_message: &str) -> Option<Vec<u8>> {
+    let failure = Message::Failure {
+        channel_id,
+        error_message: error_message.to_owned(),
+    };
+
+    bincode::serialize(&failure)
+        .map_err(|err| warn!("Failed to encode failure for channel {}: {}", channel_id, err))
+        .ok()
+}
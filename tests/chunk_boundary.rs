@@ -0,0 +1,150 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: These tests cover files whose size lands on, or just either side of,
+// a multiple of the chunk size. A file of exactly N * chunk_size bytes must be
+// sent as exactly N chunks: no trailing empty chunk and no dropped last chunk.
+
+const CHUNK_SIZE: usize = 1024;
+
+// Sizes of N * CHUNK_SIZE - 1, N * CHUNK_SIZE and N * CHUNK_SIZE + 1 for N in 1..=4,
+// along with the number of chunks each should be split into
+fn boundary_sizes() -> Vec<(usize, u32)> {
+    (1..=4)
+        .flat_map(|n| {
+            vec![
+                (n * CHUNK_SIZE - 1, n as u32),
+                (n * CHUNK_SIZE, n as u32),
+                (n * CHUNK_SIZE + 1, n as u32 + 1),
+            ]
+        })
+        .collect()
+}
+
+// Check how many chunks the protocol splits a file into
+fn assert_chunk_count(test_dir: &str, path: &str, expected: u32) {
+    let (_, _, num_chunks, _) = file_protocol::storage::initialize_file(
+        &format!("{}/count", test_dir),
+        path,
+        CHUNK_SIZE,
+        CHUNK_SIZE * 2,
+    )
+    .unwrap();
+    assert_eq!(num_chunks, expected, "Wrong chunk count for {}", path);
+}
+
+// Upload files sized around each chunk boundary
+#[test]
+fn upload_chunk_boundaries() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 10000;
+    let downlink_port = 11000;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, CHUNK_SIZE, storage_dir);
+
+    for (index, (size, num_chunks)) in boundary_sizes().into_iter().enumerate() {
+        let source = format!("{}/source_{}", test_dir_str, size);
+        let dest = format!("{}/dest_{}", test_dir_str, size);
+
+        // Each size gets unique contents so that no two files share a hash
+        let contents = vec![index as u8 + 1; size];
+
+        let hash = create_test_file(&source, &contents);
+        assert_chunk_count(test_dir_str, &source, num_chunks);
+
+        let result = upload(
+            "127.0.0.1",
+            downlink_port,
+            &format!("127.0.0.1:{}", service_port),
+            &source,
+            &dest,
+            Some(format!("{}/client", test_dir_str)),
+            CHUNK_SIZE as u32,
+        );
+
+        if let Err(err) = &result {
+            println!("Error uploading {} bytes: {}", size, err);
+        }
+
+        // The hash of the transferred file must match the source exactly
+        assert_eq!(result.unwrap(), hash);
+
+        // Verify the final file's size and contents
+        let dest_contents = fs::read(dest).unwrap();
+        assert_eq!(dest_contents.len(), size);
+        assert_eq!(contents.as_slice(), dest_contents.as_slice());
+    }
+}
+
+// Download files sized around each chunk boundary
+#[test]
+fn download_chunk_boundaries() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 10001;
+    let downlink_port = 11001;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, CHUNK_SIZE, storage_dir);
+
+    for (index, (size, num_chunks)) in boundary_sizes().into_iter().enumerate() {
+        let source = format!("{}/source_{}", test_dir_str, size);
+        let dest = format!("{}/dest_{}", test_dir_str, size);
+
+        // Each size gets unique contents so that no two files share a hash
+        let contents = vec![index as u8 + 101; size];
+
+        let hash = create_test_file(&source, &contents);
+        assert_chunk_count(test_dir_str, &source, num_chunks);
+
+        let result = download(
+            "127.0.0.1",
+            downlink_port,
+            &format!("127.0.0.1:{}", service_port),
+            &source,
+            &dest,
+            Some(format!("{}/client", test_dir_str)),
+            CHUNK_SIZE as u32,
+        );
+
+        if let Err(err) = &result {
+            println!("Error downloading {} bytes: {}", size, err);
+        }
+
+        result.unwrap();
+
+        // Verify the final file's size, contents, and hash
+        let dest_contents = fs::read(&dest).unwrap();
+        assert_eq!(dest_contents.len(), size);
+        assert_eq!(contents.as_slice(), dest_contents.as_slice());
+        assert_eq!(
+            create_test_file(&format!("{}/verify_{}", test_dir_str, size), &dest_contents),
+            hash
+        );
+    }
+}
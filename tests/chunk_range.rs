@@ -0,0 +1,97 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, Message, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Slip chunks numbered past the declared count into an upload, and check that
+// they're ignored while the upload itself still completes
+#[test]
+fn out_of_range_chunk_ignored() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10023;
+    let downlink_port = 11023;
+
+    let contents = [23; 6000];
+
+    create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let f_config = FileProtocolConfig::new(
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        5,
+        1,
+        None,
+        4096 * 2,
+    );
+    let f_protocol = FileProtocol::new(
+        &format!("127.0.0.1:{}", downlink_port),
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+        1,
+        Arc::new(Mutex::new(HashMap::new())),
+    );
+
+    let (_filename, hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_metadata(channel, &hash, num_chunks).unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+
+    for chunk_num in &[num_chunks, num_chunks + 100] {
+        send_raw(
+            service_port,
+            &Message::ReceiveChunk {
+                channel_id: channel,
+                hash: hash.clone(),
+                chunk_num: *chunk_num,
+                data: vec![0xFF; 4096],
+            },
+        );
+    }
+
+    f_protocol
+        .message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_secs(2),
+            &State::Transmitting { transmitted_files: 0, total_files: 1 },
+        )
+        .unwrap();
+
+    let dest_contents = fs::read(&dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+
+    // Neither bogus chunk should have made it into temp storage
+    for chunk_num in &[num_chunks, num_chunks + 100] {
+        let chunk = format!("{}/service/storage/{}/{}", test_dir_str, hash, chunk_num);
+        assert!(fs::metadata(chunk).is_err());
+    }
+}
@@ -21,6 +21,7 @@ use blake2_rfc::blake2s::Blake2s;
 use file_protocol::{FileProtocol, FileProtocolConfig, Message, ProtocolError, State};
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::UdpSocket;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -28,8 +29,14 @@ use std::time::Duration;
 #[macro_export]
 macro_rules! service_new {
     ($port:expr, $down_port:expr, $chunk_size:expr, $storage_dir:expr) => {{
+        service_new!($port, $down_port, $chunk_size, $storage_dir, "")
+    }};
+    ($port:expr, $down_port:expr, $chunk_size:expr, $storage_dir:expr, $extra_config:expr) => {{
+        service_new!($port, $down_port, $chunk_size, $storage_dir, $extra_config, recv_loop)
+    }};
+    ($port:expr, $down_port:expr, $chunk_size:expr, $storage_dir:expr, $extra_config:expr, $recv_loop:expr) => {{
         thread::spawn(move || {
-            recv_loop(
+            ($recv_loop)(
                 &ServiceConfig::new_from_str(
                     "file-transfer-service",
                     &format!(
@@ -40,11 +47,12 @@ macro_rules! service_new {
                 hold_count = 5
                 downlink_ip = "127.0.0.1"
                 downlink_port = {}
+                {}
                 [file-transfer-service.addr]
                 ip = "127.0.0.1"
                 port = {}
                 "#,
-                        $storage_dir, $chunk_size, $down_port, $port
+                        $storage_dir, $chunk_size, $down_port, $extra_config, $port
                     ),
                 )
                 .unwrap(),
@@ -310,3 +318,37 @@ pub fn create_test_file(name: &str, contents: &[u8]) -> String {
 
     hash_str
 }
+
+// Send a single raw message to the service from a throwaway socket
+pub fn send_raw(service_port: u16, message: &Message) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .send_to(
+            &bincode::serialize(message).unwrap(),
+            format!("127.0.0.1:{}", service_port),
+        )
+        .unwrap();
+}
+
+// Wait for the service to send a failure for `channel_id` to our downlink socket,
+// skipping anything else it sends, and return the failure's error message
+pub fn recv_failure(downlink: &UdpSocket, channel_id: u32) -> Option<String> {
+    downlink
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut buf = vec![0; 8192];
+    while let Ok((size, _)) = downlink.recv_from(&mut buf) {
+        if let Ok(Message::Failure {
+            channel_id: failed,
+            error_message,
+        }) = bincode::deserialize::<Message>(&buf[..size])
+        {
+            if failed == channel_id {
+                return Some(error_message);
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,281 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::Message;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// NOTE: The service always replies to its configured downlink port, so only one
+// client can be listening at a time. These tests occupy the service's slots with
+// raw messages instead of running overlapping transfers.
+
+// Open a channel which will never be followed up on, leaving it to hold a slot until
+// the service times it out
+fn open_hog(service_port: u16) {
+    send_raw(
+        service_port,
+        &Message::ReceiveChunk {
+            channel_id: 1,
+            hash: "0123456789abcdef0123456789abcdef".to_owned(),
+            chunk_num: 0,
+            data: vec![0; 16],
+        },
+    );
+}
+
+// Ask for a download on `channel_id` without following it up
+fn request_download(service_port: u16, channel_id: u32, source: &str) {
+    send_raw(
+        service_port,
+        &Message::ReqTransmit {
+            channel_id,
+            path: source.to_owned(),
+        },
+    );
+}
+
+// Back-to-back transfers reuse the single slot once it frees up
+#[test]
+fn transfers_reuse_slot() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 10007;
+    let downlink_port = 11007;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_concurrent_transfers = 1"
+    );
+
+    for index in 0..3 {
+        let source = format!("{}/source_{}", test_dir_str, index);
+        let dest = format!("{}/dest_{}", test_dir_str, index);
+
+        // Each transfer gets unique contents so that no two files share a hash
+        let contents = vec![index as u8 + 1; 6000];
+        create_test_file(&source, &contents);
+
+        let result = upload(
+            "127.0.0.1",
+            downlink_port,
+            &format!("127.0.0.1:{}", service_port),
+            &source,
+            &dest,
+            Some(format!("{}/client", test_dir_str)),
+            4096,
+        );
+        result.unwrap();
+
+        let dest_contents = fs::read(dest).unwrap();
+        assert_eq!(contents.as_slice(), dest_contents.as_slice());
+    }
+}
+
+// An upload arriving while the only slot is busy isn't processed until the slot frees
+// up, and then completes
+#[test]
+fn upload_queued_behind_busy_channel() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10008;
+    let downlink_port = 11008;
+
+    let contents = [8; 6000];
+    create_test_file(&source, &contents);
+
+    // A short timeout so that the channel hogging the slot gives up quickly
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_concurrent_transfers = 1\ntimeout = 1\nmax_queue_wait = 30"
+    );
+
+    let hog_opened = Instant::now();
+    open_hog(service_port);
+
+    let result = upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    // Without the limit, the upload would have run alongside the hog and finished
+    // well inside its timeout
+    assert!(
+        hog_opened.elapsed() >= Duration::from_secs(1),
+        "Upload was processed while the only slot was held"
+    );
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// A new channel is refused straight away once the queue for slots is full
+#[test]
+fn transfer_refused_when_queue_full() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let service_port = 10013;
+    let downlink_port = 11013;
+
+    create_test_file(&source, &[13; 6000]);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_concurrent_transfers = 1\nmax_queued_transfers = 0\ntimeout = 10"
+    );
+
+    let downlink = UdpSocket::bind(format!("127.0.0.1:{}", downlink_port)).unwrap();
+    open_hog(service_port);
+
+    let started = Instant::now();
+    request_download(service_port, 2, &source);
+    assert_eq!(
+        recv_failure(&downlink, 2),
+        Some("Service busy, retry later".to_owned())
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+// A queued channel which can't get a slot in time is told to retry later
+#[test]
+fn queued_transfer_told_to_retry() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let service_port = 10014;
+    let downlink_port = 11014;
+
+    create_test_file(&source, &[14; 6000]);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_concurrent_transfers = 1\nmax_queue_wait = 1\ntimeout = 10"
+    );
+
+    let downlink = UdpSocket::bind(format!("127.0.0.1:{}", downlink_port)).unwrap();
+    open_hog(service_port);
+
+    let started = Instant::now();
+    request_download(service_port, 2, &source);
+    assert_eq!(
+        recv_failure(&downlink, 2),
+        Some("Service busy, retry later".to_owned())
+    );
+
+    // It was given its full wait before being refused, but no more
+    let waited = started.elapsed();
+    assert!(waited >= Duration::from_secs(1));
+    assert!(waited < Duration::from_secs(5));
+}
+
+// A queued channel's max_channel_lifetime only starts once it gets a slot, so a long
+// wait in the queue doesn't see it reaped as soon as it starts
+#[test]
+fn queued_transfer_lifetime_starts_with_handler() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10028;
+    let downlink_port = 11028;
+
+    let contents = [28; 6000];
+    create_test_file(&source, &contents);
+
+    // The hog is only stopped by the reaper, so the upload waits out a full lifetime
+    // in the queue
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_concurrent_transfers = 1\nmax_channel_lifetime = 3\nmax_queue_wait = 30\ntimeout = 30"
+    );
+
+    let queued = Instant::now();
+    open_hog(service_port);
+
+    let result = upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+    assert!(queued.elapsed() > Duration::from_secs(3));
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// A limit which would never let a transfer run is a config error
+#[test]
+fn max_concurrent_transfers_invalid() {
+    for max in &[0, -1] {
+        let config = ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                max_concurrent_transfers = {}
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = 10015
+                "#,
+                max
+            ),
+        )
+        .unwrap();
+
+        assert!(recv_loop(&config).is_err());
+    }
+}
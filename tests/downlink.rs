@@ -0,0 +1,125 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_service::{recv_loop, ServiceError};
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start the service with a downlink address which isn't on the allow-list
+#[test]
+fn downlink_not_allowed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+
+    let config = ServiceConfig::new_from_str(
+        "file-transfer-service",
+        &format!(
+            r#"
+            [file-transfer-service]
+            storage_dir = "{}/service"
+            downlink_ip = "10.1.1.1"
+            downlink_port = 11005
+            allowed_downlinks = ["127.0.0.1", "10.1.1.2:11005"]
+            [file-transfer-service.addr]
+            ip = "127.0.0.1"
+            port = 10005
+            "#,
+            test_dir_str
+        ),
+    )
+    .unwrap();
+
+    let err = recv_loop(&config).unwrap_err();
+
+    match err.downcast_ref::<ServiceError>() {
+        Some(ServiceError::DownlinkNotAllowed { addr }) => assert_eq!(addr, "10.1.1.1:11005"),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+// An allow-list which isn't a list of addresses is a config error, not a refusal
+#[test]
+fn downlink_allow_list_invalid() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+
+    for allowed in &["\"127.0.0.1\"", "[\"127.0.0.1\", 11005]"] {
+        let config = ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                storage_dir = "{}/service"
+                downlink_ip = "127.0.0.1"
+                downlink_port = 11005
+                allowed_downlinks = {}
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = 10005
+                "#,
+                test_dir_str, allowed
+            ),
+        )
+        .unwrap();
+
+        let err = recv_loop(&config).unwrap_err();
+        assert!(err.downcast_ref::<ServiceError>().is_none(), "{}", err);
+    }
+}
+
+// Downloads to an allowed downlink address still go through
+#[test]
+fn downlink_allowed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10006;
+    let downlink_port = 11006;
+
+    let contents = "downlink_allowed".as_bytes();
+
+    create_test_file(&source, contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        format!("allowed_downlinks = [\"127.0.0.1:{}\"]", downlink_port)
+    );
+
+    let result = download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents.as_slice());
+}
@@ -0,0 +1,167 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::Message;
+use file_service::{gc, recv_loop, recv_loop_with_usage, StateUsage};
+use kubos_system::Config as ServiceConfig;
+use std::collections::HashSet;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Create a temp storage directory for a hash holding a single chunk
+fn create_hash_dir(storage_dir: &str, hash: &str, chunk: &[u8]) {
+    let path = format!("{}/storage/{}", storage_dir, hash);
+    fs::create_dir_all(&path).unwrap();
+    fs::write(format!("{}/0", path), chunk).unwrap();
+}
+
+// Stale temp storage is removed, but active hashes are left alone
+#[test]
+fn gc_skips_active() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let storage_dir = test_dir.path().to_str().unwrap();
+
+    create_hash_dir(storage_dir, "active", &[1; 100]);
+    create_hash_dir(storage_dir, "stale", &[2; 200]);
+
+    let mut active = HashSet::new();
+    active.insert("active".to_owned());
+
+    let result = gc(storage_dir, Duration::from_secs(0), &active).unwrap();
+    assert_eq!(result, (1, 200));
+
+    assert!(fs::read_dir(format!("{}/storage/active", storage_dir)).is_ok());
+    assert!(fs::read_dir(format!("{}/storage/stale", storage_dir)).is_err());
+}
+
+// Recently modified temp storage is kept
+#[test]
+fn gc_keeps_recent() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let storage_dir = test_dir.path().to_str().unwrap();
+
+    create_hash_dir(storage_dir, "recent", &[3; 100]);
+
+    let result = gc(storage_dir, Duration::from_secs(3600), &HashSet::new()).unwrap();
+    assert_eq!(result, (0, 0));
+
+    assert!(fs::read_dir(format!("{}/storage/recent", storage_dir)).is_ok());
+}
+
+// Missing temp storage isn't an error
+#[test]
+fn gc_no_storage() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let storage_dir = test_dir.path().to_str().unwrap();
+
+    let result = gc(storage_dir, Duration::from_secs(0), &HashSet::new()).unwrap();
+    assert_eq!(result, (0, 0));
+}
+
+// The service's periodic cleanup leaves the temp storage of open channels alone
+#[test]
+fn gc_skips_open_channels() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 10016;
+    let downlink_port = 11016;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let open_hash = "0123456789abcdef0123456789abcdef";
+    create_hash_dir(&storage_dir, open_hash, &[1; 100]);
+    create_hash_dir(&storage_dir, "stale", &[2; 100]);
+
+    // Everything counts as stale, but the channel outlives a few cleanup passes
+    let service_storage = storage_dir.clone();
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        service_storage,
+        "cleanup_older_than = 0\ncleanup_interval = 1\ntimeout = 10"
+    );
+
+    // Open a channel for the hash, and never follow it up
+    send_raw(
+        service_port,
+        &Message::ReceiveChunk {
+            channel_id: 1,
+            hash: open_hash.to_owned(),
+            chunk_num: 0,
+            data: vec![1; 100],
+        },
+    );
+
+    thread::sleep(Duration::from_millis(2500));
+
+    assert!(fs::read_dir(format!("{}/storage/{}", storage_dir, open_hash)).is_ok());
+    assert!(fs::read_dir(format!("{}/storage/stale", storage_dir)).is_err());
+}
+
+// Once a download is done, its prepared file's temp storage is collected like any other,
+// and the prepared file is forgotten along with it
+#[test]
+fn gc_reclaims_prepared_files() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10029;
+    let downlink_port = 11029;
+
+    let contents = [29; 6000];
+    create_test_file(&source, &contents);
+
+    let usage = StateUsage::new();
+    let service_usage = usage.clone();
+    let storage_dir = format!("{}/service", test_dir_str);
+    let service_storage = storage_dir.clone();
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        service_storage,
+        "cleanup_older_than = 0\ncleanup_interval = 1",
+        move |config: &ServiceConfig| recv_loop_with_usage(config, service_usage)
+    );
+
+    let result = download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+
+    thread::sleep(Duration::from_millis(2500));
+
+    let remaining = fs::read_dir(format!("{}/storage", storage_dir))
+        .map(|dir| dir.count())
+        .unwrap_or(0);
+    assert_eq!(remaining, 0);
+    assert_eq!(usage.bytes(), 0);
+}
@@ -0,0 +1,134 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, Message, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::collections::HashMap;
+use std::fs;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Read every file in a directory so that we can tell whether anything changed
+fn snapshot_dir(path: &str) -> Vec<(String, Vec<u8>)> {
+    let mut entries: Vec<(String, Vec<u8>)> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (path.to_str().unwrap().to_owned(), fs::read(&path).unwrap())
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+// Deliver a data chunk for a channel which has already completed its upload, and
+// check that the completion ACK is sent again
+#[test]
+fn late_chunk_after_upload() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10002;
+    let downlink_port = 11002;
+
+    let contents = [3; 5000];
+
+    let hash = create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let f_config = FileProtocolConfig::new(
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        5,
+        1,
+        None,
+        4096 * 2,
+    );
+    let f_protocol = FileProtocol::new(
+        &format!("127.0.0.1:{}", downlink_port),
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+        1,
+        Arc::new(Mutex::new(HashMap::new())),
+    );
+
+    // Run a normal upload, keeping hold of the channel ID
+    let (_filename, file_hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_metadata(channel, &file_hash, num_chunks).unwrap();
+    f_protocol.send_export(channel, &file_hash, &dest, mode).unwrap();
+    f_protocol
+        .message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_secs(2),
+            &State::Transmitting { transmitted_files: 0, total_files: 1 },
+        )
+        .unwrap();
+
+    // Give the service a moment to wrap up its side of the channel
+    thread::sleep(Duration::from_millis(100));
+
+    let service_storage = format!("{}/service/storage/{}", test_dir_str, hash);
+    let storage_before = snapshot_dir(&service_storage);
+
+    // Now send a straggler chunk carrying bogus data on the completed channel
+    let late_chunk = Message::ReceiveChunk {
+        channel_id: channel,
+        hash: file_hash.clone(),
+        chunk_num: 0,
+        data: vec![0xFF; 4096],
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .send_to(
+            &bincode::serialize(&late_chunk).unwrap(),
+            format!("127.0.0.1:{}", service_port),
+        )
+        .unwrap();
+
+    // The service should answer with the completion ACK so that we stop sending
+    let mut acked = false;
+    while let Ok(reply) = f_protocol.recv(Some(Duration::from_secs(1))) {
+        if let Ok(Message::ACK {
+            channel_id,
+            hash,
+            num_chunks: acked_chunks,
+        }) = bincode::deserialize::<Message>(&reply)
+        {
+            assert_eq!(channel_id, channel);
+            assert_eq!(hash, file_hash);
+            assert_eq!(acked_chunks, Some(num_chunks));
+            acked = true;
+            break;
+        }
+    }
+    assert!(acked, "No completion ACK received for the late chunk");
+
+    // Neither the finished file nor its temp storage should have been touched
+    let dest_contents = fs::read(&dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+    assert_eq!(storage_before, snapshot_dir(&service_storage));
+}
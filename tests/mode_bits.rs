@@ -0,0 +1,130 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::Message;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::net::UdpSocket;
+use std::os::unix::fs::PermissionsExt;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Upload a file with an ordinary mode
+#[test]
+fn upload_normal_mode_allowed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10024;
+    let downlink_port = 11024;
+
+    let contents = "normal_mode".as_bytes();
+    create_test_file(&source, contents);
+    fs::set_permissions(&source, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let result = upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents.as_slice());
+}
+
+// An upload asking for the setuid bit is refused
+#[test]
+fn upload_setuid_mode_rejected() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10025;
+    let downlink_port = 11025;
+
+    // Allowing the sticky bit doesn't let setuid through
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "allowed_special_mode_bits = 0o1000"
+    );
+
+    let downlink = UdpSocket::bind(format!("127.0.0.1:{}", downlink_port)).unwrap();
+
+    let hash = "0123456789abcdef0123456789abcdef".to_owned();
+    send_raw(
+        service_port,
+        &Message::Metadata {
+            channel_id: 25,
+            hash: hash.clone(),
+            num_chunks: 1,
+        },
+    );
+    send_raw(
+        service_port,
+        &Message::ReqReceive {
+            channel_id: 25,
+            hash,
+            path: dest.clone(),
+            mode: Some(0o4755),
+        },
+    );
+
+    assert_eq!(
+        recv_failure(&downlink, 25),
+        Some("Mode 4755 is not allowed".to_owned())
+    );
+    assert!(fs::metadata(dest).is_err());
+}
+
+// Allowing anything other than the setuid, setgid and sticky bits is a config error
+#[test]
+fn allowed_special_mode_bits_invalid() {
+    for bits in &["0o777", "-1", "\"0o4000\""] {
+        let config = ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                allowed_special_mode_bits = {}
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = 10026
+                "#,
+                bits
+            ),
+        )
+        .unwrap();
+
+        assert!(recv_loop(&config).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::Message;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Upload to a target path which matches the configured template
+#[test]
+fn upload_matching_template() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/uploads/accepted", test_dir_str);
+    let service_port = 10019;
+    let downlink_port = 11019;
+
+    fs::create_dir(format!("{}/uploads", test_dir_str)).unwrap();
+
+    let contents = "matching_template".as_bytes();
+    create_test_file(&source, contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let extra_config = format!(
+        "target_path_templates = [\"{}/uploads/{{name}}\"]",
+        test_dir_str
+    );
+    service_new!(service_port, downlink_port, 4096, storage_dir, extra_config);
+
+    let result = upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents.as_slice());
+}
+
+// An upload to a target path matching no template is refused
+#[test]
+fn upload_not_matching_template() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let dest = format!("{}/elsewhere/rejected", test_dir_str);
+    let service_port = 10020;
+    let downlink_port = 11020;
+
+    fs::create_dir(format!("{}/elsewhere", test_dir_str)).unwrap();
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let extra_config = format!(
+        "target_path_templates = [\"{}/uploads/{{name}}\"]",
+        test_dir_str
+    );
+    service_new!(service_port, downlink_port, 4096, storage_dir, extra_config);
+
+    let downlink = UdpSocket::bind(format!("127.0.0.1:{}", downlink_port)).unwrap();
+
+    let hash = "0123456789abcdef0123456789abcdef".to_owned();
+    send_raw(
+        service_port,
+        &Message::Metadata {
+            channel_id: 20,
+            hash: hash.clone(),
+            num_chunks: 1,
+        },
+    );
+    send_raw(
+        service_port,
+        &Message::ReqReceive {
+            channel_id: 20,
+            hash,
+            path: dest.clone(),
+            mode: Some(0o644),
+        },
+    );
+
+    assert_eq!(
+        recv_failure(&downlink, 20),
+        Some(format!("Target path {} is not allowed", dest))
+    );
+    assert!(fs::metadata(dest).is_err());
+}
+
+// Templates which aren't a list of paths are a config error
+#[test]
+fn target_path_templates_invalid() {
+    for templates in &["\"/home/kubos/download/{name}\"", "[\"/home/kubos/{name}\", 1]"] {
+        let config = ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                target_path_templates = {}
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = 10021
+                "#,
+                templates
+            ),
+        )
+        .unwrap();
+
+        assert!(recv_loop(&config).is_err());
+    }
+}
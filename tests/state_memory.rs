@@ -0,0 +1,191 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::Message;
+use file_service::{recv_loop_with_usage, StateUsage};
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The usage handle passed to the service reflects the state a download leaves behind
+#[test]
+fn state_usage_reported() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 10011;
+    let downlink_port = 11011;
+
+    let contents = [11; 6000];
+    create_test_file(&source, &contents);
+
+    let usage = StateUsage::new();
+    let service_usage = usage.clone();
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "",
+        move |config: &ServiceConfig| recv_loop_with_usage(config, service_usage)
+    );
+    assert_eq!(usage.bytes(), 0);
+
+    let result = download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+
+    // Give the service a moment to wrap up its side of the channel
+    thread::sleep(Duration::from_millis(500));
+    assert!(usage.bytes() > 0);
+}
+
+// Prepared files nobody is using any more are evicted once their channel ends, so
+// going over max_state_memory doesn't stop the next transfer
+#[test]
+fn state_memory_evicts_idle_entries() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 10012;
+    let downlink_port = 11012;
+
+    let usage = StateUsage::new();
+    let service_usage = usage.clone();
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_state_memory = 1",
+        move |config: &ServiceConfig| recv_loop_with_usage(config, service_usage)
+    );
+
+    for (name, contents) in &[("first", [12; 6000]), ("second", [13; 6000])] {
+        let source = format!("{}/{}", test_dir_str, name);
+        let dest = format!("{}/{}_dest", test_dir_str, name);
+        create_test_file(&source, contents);
+
+        let result = download(
+            "127.0.0.1",
+            downlink_port,
+            &format!("127.0.0.1:{}", service_port),
+            &source,
+            &dest,
+            Some(format!("{}/client", test_dir_str)),
+            4096,
+        );
+        result.unwrap();
+
+        let dest_contents = fs::read(dest).unwrap();
+        assert_eq!(&contents[..], dest_contents.as_slice());
+
+        // Once the channel is done, its prepared file no longer counts
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(usage.bytes(), 0);
+    }
+}
+
+// New channels are refused while an open channel holds state over max_state_memory,
+// and admitted again once it's gone
+#[test]
+fn state_memory_refuses_channels_while_in_use() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 10027;
+    let downlink_port = 11027;
+
+    let hog_source = format!("{}/hog", test_dir_str);
+    create_test_file(&hog_source, &[27; 6000]);
+
+    let usage = StateUsage::new();
+    let service_usage = usage.clone();
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_state_memory = 1\ntimeout = 1",
+        move |config: &ServiceConfig| recv_loop_with_usage(config, service_usage)
+    );
+
+    {
+        let downlink = UdpSocket::bind(format!("127.0.0.1:{}", downlink_port)).unwrap();
+
+        // Ask for a download and never acknowledge it, so its prepared file stays in use
+        send_raw(
+            service_port,
+            &Message::ReqTransmit {
+                channel_id: 1,
+                path: hog_source,
+            },
+        );
+        thread::sleep(Duration::from_millis(500));
+        assert!(usage.bytes() > 1);
+
+        send_raw(
+            service_port,
+            &Message::ReqTransmit {
+                channel_id: 2,
+                path: format!("{}/other", test_dir_str),
+            },
+        );
+        assert_eq!(
+            recv_failure(&downlink, 2),
+            Some("Transfer state over max_state_memory, retry later".to_owned())
+        );
+    }
+
+    // Once the first channel gives up, there's room again
+    thread::sleep(Duration::from_secs(5));
+
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let contents = [28; 6000];
+    create_test_file(&source, &contents);
+    let result = download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
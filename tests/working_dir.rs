@@ -0,0 +1,129 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::Message;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Upload and download using relative paths which the service resolves
+// against its configured working directory
+#[test]
+fn relative_paths_use_working_dir() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let working_dir = format!("{}/working", test_dir_str);
+    fs::create_dir(&working_dir).unwrap();
+    let service_port = 10003;
+    let downlink_port = 11003;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let extra_config = format!("working_dir = \"{}\"", working_dir);
+    service_new!(service_port, downlink_port, 4096, storage_dir, extra_config);
+
+    // Upload to a relative target path
+    let upload_source = format!("{}/upload_source", test_dir_str);
+    let upload_contents = "relative_upload".as_bytes();
+    create_test_file(&upload_source, upload_contents);
+
+    let result = upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &upload_source,
+        "uploaded",
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(format!("{}/uploaded", working_dir)).unwrap();
+    assert_eq!(upload_contents, dest_contents.as_slice());
+
+    // Download from a relative source path
+    let download_contents = "relative_download".as_bytes();
+    create_test_file(&format!("{}/to_download", working_dir), download_contents);
+    let download_dest = format!("{}/download_dest", test_dir_str);
+
+    let result = download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        "to_download",
+        &download_dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    result.unwrap();
+
+    let dest_contents = fs::read(download_dest).unwrap();
+    assert_eq!(download_contents, dest_contents.as_slice());
+}
+
+// A relative path is checked against the target path templates once it's resolved,
+// so it can't use `..` to land outside of them
+#[test]
+fn relative_path_checked_against_templates() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let working_dir = format!("{}/working", test_dir_str);
+    fs::create_dir(&working_dir).unwrap();
+    let service_port = 10022;
+    let downlink_port = 11022;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let extra_config = format!(
+        "working_dir = \"{}\"\ntarget_path_templates = [\"{}/{{name}}\"]",
+        working_dir,
+        fs::canonicalize(&working_dir).unwrap().display()
+    );
+    service_new!(service_port, downlink_port, 4096, storage_dir, extra_config);
+
+    let downlink = UdpSocket::bind(format!("127.0.0.1:{}", downlink_port)).unwrap();
+
+    let hash = "0123456789abcdef0123456789abcdef".to_owned();
+    send_raw(
+        service_port,
+        &Message::Metadata {
+            channel_id: 22,
+            hash: hash.clone(),
+            num_chunks: 1,
+        },
+    );
+    send_raw(
+        service_port,
+        &Message::ReqReceive {
+            channel_id: 22,
+            hash,
+            path: "../escaped".to_owned(),
+            mode: Some(0o644),
+        },
+    );
+
+    let escaped = fs::canonicalize(test_dir_str).unwrap().join("escaped");
+    assert_eq!(
+        recv_failure(&downlink, 22),
+        Some(format!("Target path {} is not allowed", escaped.display()))
+    );
+    assert!(fs::metadata(escaped).is_err());
+}